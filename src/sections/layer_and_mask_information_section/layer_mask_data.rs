@@ -1,16 +1,118 @@
 use super::PsdLayerError;
 use crate::PsdCursor;
 
-const POSITION_RELATIVE_TO_LAYER: u8 = 0b0000_0001;
-const LAYER_MASK_DISABLED: u8 = 0b0000_0010;
-const INVERT_LAYER_MASK_WHEN_BLENDING: u8 = 0b0000_0100;
-const USER_MASK_CAME_FROM_RENDERING_OTHER_DATA: u8 = 0b0000_1000;
-const MASKS_HAVE_PARAMETERS_APPLIED: u8 = 0b0001_0000;
+/// One bit of a layer mask record's flags: either one of the five "Flags"
+/// bits in the main record, or one of the four parameter-presence bits in
+/// the optional parameter sub-record (which live in a separate byte, but
+/// describe the same mask).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerMaskFlag {
+    PositionRelativeToLayer,
+    LayerMaskDisabled,
+    /// Obsolete.
+    InvertLayerMaskWhenBlending,
+    UserMaskCameFromRenderingOtherData,
+    MasksHaveParametersApplied,
+    UserMaskDensity,
+    UserMaskFeather,
+    VectorMaskDensity,
+    VectorMaskFeather,
+}
+
+impl LayerMaskFlag {
+    const ALL: [LayerMaskFlag; 9] = [
+        LayerMaskFlag::PositionRelativeToLayer,
+        LayerMaskFlag::LayerMaskDisabled,
+        LayerMaskFlag::InvertLayerMaskWhenBlending,
+        LayerMaskFlag::UserMaskCameFromRenderingOtherData,
+        LayerMaskFlag::MasksHaveParametersApplied,
+        LayerMaskFlag::UserMaskDensity,
+        LayerMaskFlag::UserMaskFeather,
+        LayerMaskFlag::VectorMaskDensity,
+        LayerMaskFlag::VectorMaskFeather,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            LayerMaskFlag::PositionRelativeToLayer => "PositionRelativeToLayer",
+            LayerMaskFlag::LayerMaskDisabled => "LayerMaskDisabled",
+            LayerMaskFlag::InvertLayerMaskWhenBlending => "InvertLayerMaskWhenBlending",
+            LayerMaskFlag::UserMaskCameFromRenderingOtherData => "UserMaskCameFromRenderingOtherData",
+            LayerMaskFlag::MasksHaveParametersApplied => "MasksHaveParametersApplied",
+            LayerMaskFlag::UserMaskDensity => "UserMaskDensity",
+            LayerMaskFlag::UserMaskFeather => "UserMaskFeather",
+            LayerMaskFlag::VectorMaskDensity => "VectorMaskDensity",
+            LayerMaskFlag::VectorMaskFeather => "VectorMaskFeather",
+        }
+    }
+}
+
+/// The five layer-mask "Flags" bits plus the four parameter-presence bits,
+/// queryable as a single structure (following the GDAL `GdalMaskFlags`
+/// pattern) instead of scattered boolean methods and private bitmask
+/// constants. Querying `contains(UserMaskDensity)` lets callers tell a
+/// density that was never specified apart from one explicitly set to 255 -
+/// both parse to the same `LayerMaskDataInner::density` value otherwise.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LayerMaskFlags {
+    flags: u8,
+    parameter_flags: u8,
+}
+
+impl LayerMaskFlags {
+    pub fn new(flags: u8, parameter_flags: u8) -> Self {
+        LayerMaskFlags {
+            flags,
+            parameter_flags,
+        }
+    }
+
+    pub fn contains(&self, flag: LayerMaskFlag) -> bool {
+        match flag {
+            LayerMaskFlag::PositionRelativeToLayer => self.flags & 0b0000_0001 != 0,
+            LayerMaskFlag::LayerMaskDisabled => self.flags & 0b0000_0010 != 0,
+            LayerMaskFlag::InvertLayerMaskWhenBlending => self.flags & 0b0000_0100 != 0,
+            LayerMaskFlag::UserMaskCameFromRenderingOtherData => self.flags & 0b0000_1000 != 0,
+            LayerMaskFlag::MasksHaveParametersApplied => self.flags & 0b0001_0000 != 0,
+            LayerMaskFlag::UserMaskDensity => self.parameter_flags & 0b0000_0001 != 0,
+            LayerMaskFlag::UserMaskFeather => self.parameter_flags & 0b0000_0010 != 0,
+            LayerMaskFlag::VectorMaskDensity => self.parameter_flags & 0b0000_0100 != 0,
+            LayerMaskFlag::VectorMaskFeather => self.parameter_flags & 0b0000_1000 != 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = LayerMaskFlag> + '_ {
+        LayerMaskFlag::ALL.into_iter().filter(move |flag| self.contains(*flag))
+    }
+}
+
+impl std::fmt::Debug for LayerMaskFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.iter().map(LayerMaskFlag::name)).finish()
+    }
+}
+
+/// Lets `LayerMaskData` fetch whatever it needs from the layer that owns it
+/// (its raw mask channel bytes, and - for `masked_rgba_for` - its bounds and
+/// decoded RGBA), so callers can go straight from a layer to masked pixels
+/// instead of manually correlating mask rectangles, the layer rectangle, and
+/// `position_relative_to_layer()` themselves. A layer type implements this
+/// once; every mask consumer then gets the high-level `_for` methods below.
+pub trait MaskChannelSource {
+    /// The layer's own bounding box: `(top, left, right, bottom)`.
+    fn bounds(&self) -> (i32, i32, i32, i32);
+
+    /// The layer's already-decoded RGBA pixels, `width * height * 4` bytes.
+    fn rgba(&self) -> &[u8];
+
+    /// Raw, already-decompressed vector mask channel bytes, if the layer
+    /// has a vector mask.
+    fn vector_mask_channel(&self) -> Option<&[u8]>;
 
-const USER_MASK_DENSITY: u8 = 0b0000_0001;
-const USER_MASK_FEATHER: u8 = 0b0000_0010;
-const VECTOR_MASK_DENSITY: u8 = 0b0000_0100;
-const VECTOR_MASK_FEATHER: u8 = 0b0000_1000;
+    /// Raw, already-decompressed raster (user) mask channel bytes, if the
+    /// layer has a raster mask.
+    fn raster_mask_channel(&self) -> Option<&[u8]>;
+}
 
 #[derive(Debug, Clone)]
 pub struct LayerMaskData {
@@ -18,6 +120,147 @@ pub struct LayerMaskData {
     pub raster_mask: Option<LayerMaskDataInner>,
 }
 
+impl LayerMaskData {
+    /// Rasterizes whichever mask is present, preferring the raster mask
+    /// since that's the one Photoshop renders with when both are stored.
+    /// `mask_channel` is the raw, already-decompressed channel bytes for
+    /// that mask (see `LayerMaskDataInner::rasterize`).
+    pub fn rasterize(&self, mask_channel: &[u8]) -> Option<Vec<u8>> {
+        self.raster_mask
+            .as_ref()
+            .or(self.vector_mask.as_ref())
+            .map(|mask| mask.rasterize(mask_channel))
+    }
+
+    /// Like `rasterize`, but fetches the raw mask channel bytes itself from
+    /// `source` instead of making the caller source and pass them.
+    pub fn rasterize_for(&self, source: &impl MaskChannelSource) -> Option<Vec<u8>> {
+        let raster = self
+            .raster_mask
+            .as_ref()
+            .zip(source.raster_mask_channel())
+            .map(|(mask, channel)| mask.rasterize(channel));
+        if raster.is_some() {
+            return raster;
+        }
+        self.vector_mask
+            .as_ref()
+            .zip(source.vector_mask_channel())
+            .map(|(mask, channel)| mask.rasterize(channel))
+    }
+
+    /// Applies this layer's mask(s) to an already-decoded RGBA buffer for
+    /// that layer, returning new RGBA pixels with the masks multiplied into
+    /// the alpha channel. When both `vector_mask` and `raster_mask` are
+    /// present their alphas are combined the way Photoshop does: multiplied
+    /// together.
+    ///
+    /// `layer_top`/`layer_left`/`layer_right`/`layer_bottom` are the layer's
+    /// own bounding box, used to translate mask coordinates into the layer's
+    /// pixel space: relative when `position_relative_to_layer()` is set,
+    /// canvas-absolute otherwise. `rgba` must already be `layer_width *
+    /// layer_height * 4` bytes. `vector_mask_channel`/`raster_mask_channel`
+    /// are the raw, already-decompressed mask channel bytes for each mask,
+    /// fetched by the caller.
+    pub fn masked_rgba(
+        &self,
+        layer_top: i32,
+        layer_left: i32,
+        layer_right: i32,
+        layer_bottom: i32,
+        rgba: &[u8],
+        vector_mask_channel: Option<&[u8]>,
+        raster_mask_channel: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let layer_width = (layer_right - layer_left).max(0);
+        let layer_height = (layer_bottom - layer_top).max(0);
+
+        let vector_alpha = self
+            .vector_mask
+            .as_ref()
+            .zip(vector_mask_channel)
+            .map(|(mask, channel)| (mask, mask.rasterize(channel)));
+        let raster_alpha = self
+            .raster_mask
+            .as_ref()
+            .zip(raster_mask_channel)
+            .map(|(mask, channel)| (mask, mask.rasterize(channel)));
+
+        if vector_alpha.is_none() && raster_alpha.is_none() {
+            return rgba.to_vec();
+        }
+
+        let mut out = rgba.to_vec();
+
+        for y in 0..layer_height {
+            for x in 0..layer_width {
+                let mut combined = 255u16;
+
+                if let Some((mask, alpha)) = &vector_alpha {
+                    combined = combined * sample_mask_at(mask, layer_top, layer_left, x, y, alpha) as u16 / 255;
+                }
+                if let Some((mask, alpha)) = &raster_alpha {
+                    combined = combined * sample_mask_at(mask, layer_top, layer_left, x, y, alpha) as u16 / 255;
+                }
+
+                let alpha_index = (y as usize * layer_width as usize + x as usize) * 4 + 3;
+                if let Some(existing) = out.get_mut(alpha_index) {
+                    *existing = (*existing as u16 * combined / 255) as u8;
+                }
+            }
+        }
+
+        out
+    }
+
+    /// Like `masked_rgba`, but fetches the layer's bounds, decoded RGBA, and
+    /// mask channel bytes itself from `source` instead of making the caller
+    /// source and pass them - the ergonomic entry point for "just give me
+    /// this layer's final masked pixels".
+    pub fn masked_rgba_for(&self, source: &impl MaskChannelSource) -> Vec<u8> {
+        let (top, left, right, bottom) = source.bounds();
+        self.masked_rgba(
+            top,
+            left,
+            right,
+            bottom,
+            source.rgba(),
+            source.vector_mask_channel(),
+            source.raster_mask_channel(),
+        )
+    }
+}
+
+/// Samples a rasterized mask at a layer-local pixel, translating through
+/// `position_relative_to_layer()`. Pixels outside the mask's own bounding
+/// box take the mask's `default_color`, matching how Photoshop treats areas
+/// a mask doesn't cover.
+fn sample_mask_at(
+    mask: &LayerMaskDataInner,
+    layer_top: i32,
+    layer_left: i32,
+    layer_x: i32,
+    layer_y: i32,
+    alpha: &[u8],
+) -> u8 {
+    let (mask_x, mask_y) = if mask.position_relative_to_layer() {
+        (layer_x - mask.left, layer_y - mask.top)
+    } else {
+        (layer_left + layer_x - mask.left, layer_top + layer_y - mask.top)
+    };
+
+    if mask_x < 0 || mask_y < 0 || mask_x >= mask.width() || mask_y >= mask.height() {
+        return mask.default_color;
+    }
+
+    // `alpha` may be shorter than `mask.width() * mask.height()` implies -
+    // `rasterize` returns an empty buffer instead of allocating for a
+    // rectangle over `MAX_MASK_PIXELS`. Fall back to `default_color` rather
+    // than indexing out of bounds.
+    let index = mask_y as usize * mask.width() as usize + mask_x as usize;
+    alpha.get(index).copied().unwrap_or(mask.default_color)
+}
+
 #[derive(Debug, Clone)]
 pub struct LayerMaskDataInner {
     pub top: i32,
@@ -26,15 +269,214 @@ pub struct LayerMaskDataInner {
     pub right: i32,
     pub default_color: u8,
     pub flags: u8,
+    /// Raw parameter-presence byte, if the record's first mask has
+    /// `MasksHaveParametersApplied` set in its `flags` and the record wasn't
+    /// truncated before it could be read. 0 otherwise. Both masks of a record
+    /// get the same byte.
+    pub parameter_flags: u8,
     pub density: u8,
     pub feather: f64,
 }
+/// Upper bound on a rasterized mask's pixel count. Real masks never get
+/// close to this; it exists to reject the width/height a crafted or
+/// corrupt record can claim before `rasterize` tries to allocate for it.
+const MAX_MASK_PIXELS: usize = 64 * 1024 * 1024;
+
+/// Upper bound on the Gaussian blur radius used to feather a mask,
+/// regardless of how large `feather` claims to be - beyond this the extra
+/// blur isn't visually meaningful, and without a cap a huge `feather` value
+/// would make `gaussian_kernel` try to allocate an enormous kernel.
+const MAX_GAUSSIAN_RADIUS: i32 = 256;
+
+/// Upper bound on total blur work (`pixel count * kernel radius`) a single
+/// `rasterize` call will perform while feathering. `MAX_MASK_PIXELS` and
+/// `MAX_GAUSSIAN_RADIUS` each bound memory on their own, but a mask just
+/// under the pixel cap combined with a feather that hits the radius cap
+/// still drives `convolve_1d` through tens of billions of multiply-adds -
+/// an allocation-sized mask doesn't need an allocation-sized blur radius
+/// too. Shrinking the effective radius as the mask grows keeps the CPU
+/// work bounded regardless of how pixel count and feather are combined.
+/// The radius never drops below 1, so a mask of more than `MAX_BLUR_WORK`
+/// pixels still costs one radius per pixel and goes over this bound.
+const MAX_BLUR_WORK: usize = 16 * 1024 * 1024;
+
 impl LayerMaskDataInner {
+    /// The mask's flag bits as a single queryable structure, instead of
+    /// reaching for `flags`/`parameter_flags` and the bitmasks by hand.
+    pub fn mask_flags(&self) -> LayerMaskFlags {
+        LayerMaskFlags::new(self.flags, self.parameter_flags)
+    }
+
+    pub fn width(&self) -> i32 {
+        self.right - self.left
+    }
+
     pub fn height(&self) -> i32 {
         self.bottom - self.top
     }
+
+    /// Bakes this mask's density and feather settings into a per-pixel,
+    /// 8-bit alpha buffer over the mask's bounding box (`width() x height()`),
+    /// mirroring Blender's `BKE_mask_rasterize` evaluation of feather +
+    /// density.
+    ///
+    /// `mask_channel` is the already-decompressed mask channel, row-major
+    /// over the same bounding box. If it's shorter than `width() * height()`
+    /// (a truncated record), the missing samples are filled with
+    /// `default_color`.
+    pub fn rasterize(&self, mask_channel: &[u8]) -> Vec<u8> {
+        let width = self.width().max(0) as usize;
+        let height = self.height().max(0) as usize;
+        let len = width.saturating_mul(height);
+
+        // A crafted/corrupt record can claim an arbitrarily large
+        // rectangle; refuse to allocate for it instead of letting a
+        // multi-gigabyte `vec!` abort the process.
+        if len == 0 || len > MAX_MASK_PIXELS {
+            return Vec::new();
+        }
+
+        if self.layer_mask_disabled() {
+            return vec![255; len];
+        }
+
+        let mut buffer = vec![self.default_color; len];
+        let copied = mask_channel.len().min(len);
+        buffer[..copied].copy_from_slice(&mask_channel[..copied]);
+
+        if self.invert_layer_mask_when_blending() {
+            for sample in buffer.iter_mut() {
+                *sample = 255 - *sample;
+            }
+        }
+
+        if self.feather != 0.0 {
+            buffer = gaussian_blur(&buffer, width, height, self.feather / 2.0);
+        }
+
+        if self.density != 255 {
+            let density = self.density as f32 / 255.0;
+            for sample in buffer.iter_mut() {
+                *sample = (*sample as f32 * density).round() as u8;
+            }
+        }
+
+        buffer
+    }
+}
+
+/// Separable Gaussian blur, clamping at the buffer edges rather than
+/// sampling out of bounds. Used to feather rasterized masks.
+fn gaussian_blur(buffer: &[u8], width: usize, height: usize, sigma: f64) -> Vec<u8> {
+    if width == 0 || height == 0 || sigma <= 0.0 {
+        return buffer.to_vec();
+    }
+
+    // Cap pixel_count * radius rather than capping radius alone, so a mask
+    // near MAX_MASK_PIXELS can't also demand a near-MAX_GAUSSIAN_RADIUS blur.
+    let max_radius = (MAX_BLUR_WORK / width.saturating_mul(height).max(1))
+        .clamp(1, MAX_GAUSSIAN_RADIUS as usize) as i32;
+
+    let kernel = gaussian_kernel(sigma, max_radius);
+    let blurred_rows = convolve_1d(buffer, width, height, &kernel, true);
+    convolve_1d(&blurred_rows, width, height, &kernel, false)
+}
+
+fn gaussian_kernel(sigma: f64, max_radius: i32) -> Vec<f64> {
+    let radius = (sigma * 3.0).ceil().max(1.0).min(max_radius as f64) as i32;
+    let mut kernel: Vec<f64> = (-radius..=radius)
+        .map(|x| (-(x as f64 * x as f64) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let sum: f64 = kernel.iter().sum();
+    for weight in kernel.iter_mut() {
+        *weight /= sum;
+    }
+    kernel
+}
+
+fn convolve_1d(buffer: &[u8], width: usize, height: usize, kernel: &[f64], horizontal: bool) -> Vec<u8> {
+    let radius = (kernel.len() / 2) as i32;
+    let mut out = vec![0u8; buffer.len()];
+
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let mut acc = 0.0;
+            for (i, weight) in kernel.iter().enumerate() {
+                let offset = i as i32 - radius;
+                let (sample_x, sample_y) = if horizontal {
+                    ((x + offset).clamp(0, width as i32 - 1), y)
+                } else {
+                    (x, (y + offset).clamp(0, height as i32 - 1))
+                };
+                acc += buffer[sample_y as usize * width + sample_x as usize] as f64 * weight;
+            }
+            out[y as usize * width + x as usize] = acc.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    out
 }
 
+/// Whether `read_layer_mask_data_with_mode` should fail on a record whose
+/// declared `layer_mask_data_len` doesn't cover the fields it claims to
+/// have (via its flags), or should instead keep whatever parsed cleanly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskParseMode {
+    /// Bail out with `TruncatedLayerMaskDataError` as soon as a record is
+    /// found to be shorter than its flags imply.
+    Strict,
+    /// Stop reading further fields once a record is found to be shorter
+    /// than its flags imply, keeping whatever masks parsed fully.
+    Lenient,
+}
+
+/// Which of the optional sections in a layer mask record were actually
+/// present, and whether the record had to be recovered from a declared
+/// length that didn't match its fields.
+///
+/// A `false` here for e.g. `raster_mask_density_present` means the mask's
+/// `density` is the default (255), not that it was explicitly set to 255 -
+/// that distinction is otherwise lost once parsing collapses both cases to
+/// the same field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LayerMaskParseDiagnostics {
+    pub second_mask_present: bool,
+    pub raster_mask_density_present: bool,
+    pub raster_mask_feather_present: bool,
+    pub vector_mask_density_present: bool,
+    pub vector_mask_feather_present: bool,
+    /// The record's declared `layer_mask_data_len` didn't cover every field
+    /// its flags claimed to have; reading stopped early and whatever parsed
+    /// fully was kept.
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerMaskDataParseResult {
+    pub data: LayerMaskData,
+    pub diagnostics: LayerMaskParseDiagnostics,
+}
+
+/// A layer mask record declared a `layer_mask_data_len` too short for the
+/// fields its own flags say it has.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TruncatedLayerMaskDataError {
+    pub layer_mask_data_len: u32,
+    pub read_count: u32,
+}
+
+impl std::fmt::Display for TruncatedLayerMaskDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "layer mask data declared a length of {} bytes, but {} bytes were needed to read the fields its flags claim to have",
+            self.layer_mask_data_len, self.read_count
+        )
+    }
+}
+
+impl std::error::Error for TruncatedLayerMaskDataError {}
+
 /// https://www.adobe.com/devnet-apps/photoshop/fileformatashtml/#50577409_26431
 /// See Layer mask / adjustment layer data for structure. Can be 40 bytes, 24 bytes, or 4 bytes if no layer mask.
 ///
@@ -44,16 +486,41 @@ impl LayerMaskDataInner {
 /// - bit 2 = invert layer mask when blending (Obsolete)
 /// - bit 3 = indicates that the user mask actually came from rendering other data
 /// - bit 4 = indicates that the user and/or vector masks have parameters applied to them
+///
+/// Always parses leniently; use `read_layer_mask_data_with_mode` to opt into
+/// `MaskParseMode::Strict` for untrusted input.
 pub fn read_layer_mask_data(cursor: &mut PsdCursor) -> Result<LayerMaskData, PsdLayerError> {
+    let result = read_layer_mask_data_with_mode(cursor, MaskParseMode::Lenient)
+        .expect("lenient mode never returns Err");
+    Ok(result.data)
+}
+
+/// Like `read_layer_mask_data`, but reports which optional sections were
+/// present and lets the caller choose how to handle a record whose declared
+/// `layer_mask_data_len` doesn't match the fields its flags claim to have.
+///
+/// In `MaskParseMode::Lenient`, such a record stops reading further fields
+/// and keeps whatever masks parsed fully, reporting `truncated: true`. In
+/// `MaskParseMode::Strict` it returns `Err` instead.
+pub fn read_layer_mask_data_with_mode(
+    cursor: &mut PsdCursor,
+    mode: MaskParseMode,
+) -> Result<LayerMaskDataParseResult, TruncatedLayerMaskDataError> {
     let layer_mask_data_len = cursor.read_u32();
+    let data_start = cursor.position();
+    let mut diagnostics = LayerMaskParseDiagnostics::default();
+
     if layer_mask_data_len < 16 {
         cursor.read(layer_mask_data_len);
-        return Ok(LayerMaskData {
-            vector_mask: None,
-            raster_mask: None,
+        return Ok(LayerMaskDataParseResult {
+            data: LayerMaskData {
+                vector_mask: None,
+                raster_mask: None,
+            },
+            diagnostics,
         });
     }
-    let mut read_count = 0;
+    let mut read_count: u32 = 0;
 
     let first_mask = {
         let top = cursor.read_i32();
@@ -75,12 +542,15 @@ pub fn read_layer_mask_data(cursor: &mut PsdCursor) -> Result<LayerMaskData, Psd
             right,
             default_color,
             flags,
+            parameter_flags: 0,
             density: 255,
             feather: 0.0,
         }
     };
 
-    let second_mask = if layer_mask_data_len - read_count >= 18 {
+    let second_mask = if layer_mask_data_len.saturating_sub(read_count) >= 18 {
+        diagnostics.second_mask_present = true;
+
         let flags = cursor.read_u8();
         read_count += 1;
 
@@ -100,6 +570,7 @@ pub fn read_layer_mask_data(cursor: &mut PsdCursor) -> Result<LayerMaskData, Psd
             right,
             default_color,
             flags,
+            parameter_flags: 0,
             density: 255,
             feather: 0.0,
         })
@@ -107,87 +578,463 @@ pub fn read_layer_mask_data(cursor: &mut PsdCursor) -> Result<LayerMaskData, Psd
         None
     };
 
-    let mut raster_mask_density = 0;
+    // The fixed-size mask header(s) above are read unconditionally once
+    // `layer_mask_data_len >= 16`, regardless of whether the declared length
+    // actually covers them (e.g. 16 or 17). Catch that here instead of only
+    // inside the `MasksHaveParametersApplied` branch below, otherwise such a
+    // record is reported as a clean parse even though the cursor already
+    // read past its declared length.
+    if read_count > layer_mask_data_len {
+        diagnostics.truncated = true;
+    }
+
+    // 255 (fully opaque, "not specified") is the correct default here, not 0:
+    // `MasksHaveParametersApplied` is rarely set in real files, and every
+    // mask without it must fall through to this default rather than being
+    // read as fully transparent.
+    let mut raster_mask_density = 255;
     let mut raster_mask_feather = 0.0;
-    let mut vector_mask_density = 0;
+    let mut vector_mask_density = 255;
     let mut vector_mask_feather = 0.0;
-    if first_mask.flags & MASKS_HAVE_PARAMETERS_APPLIED != 0 {
-        let parameter_flags = cursor.read_u8();
-        read_count += 1;
+    let mut parameter_flags = 0u8;
 
-        if parameter_flags & USER_MASK_DENSITY != 0 {
-            raster_mask_density = cursor.read_u8();
+    if first_mask.mask_flags().contains(LayerMaskFlag::MasksHaveParametersApplied) {
+        if read_count >= layer_mask_data_len {
+            diagnostics.truncated = true;
+        } else {
+            parameter_flags = cursor.read_u8();
             read_count += 1;
-        }
+            let flags = LayerMaskFlags::new(0, parameter_flags);
 
-        if parameter_flags & USER_MASK_FEATHER != 0 {
-            raster_mask_feather = cursor.read_f64();
-            read_count += 8;
-        }
+            if flags.contains(LayerMaskFlag::UserMaskDensity) && !diagnostics.truncated {
+                if read_count + 1 <= layer_mask_data_len {
+                    raster_mask_density = cursor.read_u8();
+                    read_count += 1;
+                    diagnostics.raster_mask_density_present = true;
+                } else {
+                    diagnostics.truncated = true;
+                }
+            }
 
-        if parameter_flags & VECTOR_MASK_DENSITY != 0 {
-            vector_mask_density = cursor.read_u8();
-            read_count += 1;
-        }
+            if flags.contains(LayerMaskFlag::UserMaskFeather) && !diagnostics.truncated {
+                if read_count + 8 <= layer_mask_data_len {
+                    raster_mask_feather = cursor.read_f64();
+                    read_count += 8;
+                    diagnostics.raster_mask_feather_present = true;
+                } else {
+                    diagnostics.truncated = true;
+                }
+            }
+
+            if flags.contains(LayerMaskFlag::VectorMaskDensity) && !diagnostics.truncated {
+                if read_count + 1 <= layer_mask_data_len {
+                    vector_mask_density = cursor.read_u8();
+                    read_count += 1;
+                    diagnostics.vector_mask_density_present = true;
+                } else {
+                    diagnostics.truncated = true;
+                }
+            }
 
-        if parameter_flags & VECTOR_MASK_FEATHER != 0 {
-            vector_mask_feather = cursor.read_f64();
-            read_count += 8;
+            if flags.contains(LayerMaskFlag::VectorMaskFeather) && !diagnostics.truncated {
+                if read_count + 8 <= layer_mask_data_len {
+                    vector_mask_feather = cursor.read_f64();
+                    read_count += 8;
+                    diagnostics.vector_mask_feather_present = true;
+                } else {
+                    diagnostics.truncated = true;
+                }
+            }
         }
     }
 
-    // Skip remaining bytes
-    cursor.read(layer_mask_data_len - read_count);
+    if diagnostics.truncated && mode == MaskParseMode::Strict {
+        return Err(TruncatedLayerMaskDataError {
+            layer_mask_data_len,
+            read_count,
+        });
+    }
+
+    // Realign to the record's declared end rather than skipping
+    // `layer_mask_data_len - read_count` more bytes: once a record is
+    // truncated (e.g. a header too large for its own declared length),
+    // read_count has already overshot layer_mask_data_len, and a relative
+    // skip is a no-op that leaves the cursor positioned mid-overshoot -
+    // every field after this record would then be read from the wrong
+    // offset. Seeking to the absolute end point corrects both cases: it
+    // skips forward when read_count fell short, and rewinds past whatever
+    // was over-read when it didn't.
+    cursor.set_position(data_start + layer_mask_data_len as u64);
 
     let mut layer_mask_data = if let Some(second_mask) = second_mask {
         LayerMaskData {
             vector_mask: Some(first_mask),
             raster_mask: Some(second_mask),
         }
+    } else if first_mask.user_mask_came_from_rendering_other_data() {
+        LayerMaskData {
+            vector_mask: Some(first_mask),
+            raster_mask: None,
+        }
     } else {
-        if first_mask.user_mask_came_from_rendering_other_data() {
-            LayerMaskData {
-                vector_mask: Some(first_mask),
-                raster_mask: None,
-            }
-        } else {
-            LayerMaskData {
-                vector_mask: None,
-                raster_mask: Some(first_mask),
-            }
+        LayerMaskData {
+            vector_mask: None,
+            raster_mask: Some(first_mask),
         }
     };
 
     if let Some(raster_mask) = layer_mask_data.raster_mask.as_mut() {
         raster_mask.density = raster_mask_density;
         raster_mask.feather = raster_mask_feather;
+        raster_mask.parameter_flags = parameter_flags;
     }
     if let Some(vector_mask) = layer_mask_data.vector_mask.as_mut() {
         vector_mask.density = vector_mask_density;
         vector_mask.feather = vector_mask_feather;
+        vector_mask.parameter_flags = parameter_flags;
     }
 
-    Ok(layer_mask_data)
+    Ok(LayerMaskDataParseResult {
+        data: layer_mask_data,
+        diagnostics,
+    })
 }
 
 impl LayerMaskDataInner {
     pub fn position_relative_to_layer(&self) -> bool {
-        self.flags & POSITION_RELATIVE_TO_LAYER != 0
+        self.mask_flags().contains(LayerMaskFlag::PositionRelativeToLayer)
     }
 
     pub fn layer_mask_disabled(&self) -> bool {
-        self.flags & LAYER_MASK_DISABLED != 0
+        self.mask_flags().contains(LayerMaskFlag::LayerMaskDisabled)
     }
 
     pub fn invert_layer_mask_when_blending(&self) -> bool {
-        self.flags & INVERT_LAYER_MASK_WHEN_BLENDING != 0
+        self.mask_flags().contains(LayerMaskFlag::InvertLayerMaskWhenBlending)
     }
 
     pub fn user_mask_came_from_rendering_other_data(&self) -> bool {
-        self.flags & USER_MASK_CAME_FROM_RENDERING_OTHER_DATA != 0
+        self.mask_flags().contains(LayerMaskFlag::UserMaskCameFromRenderingOtherData)
     }
 
     pub fn user_and_or_vector_masks_have_parameters_applied(&self) -> bool {
-        self.flags & MASKS_HAVE_PARAMETERS_APPLIED != 0
+        self.mask_flags().contains(LayerMaskFlag::MasksHaveParametersApplied)
+    }
+
+    /// Whether this record specified a raster (user) mask density, as
+    /// opposed to leaving it at the default - both otherwise collapse to
+    /// the same `density` value after parsing.
+    pub fn user_mask_density_specified(&self) -> bool {
+        self.mask_flags().contains(LayerMaskFlag::UserMaskDensity)
+    }
+
+    /// Whether this record specified a raster (user) mask feather, as
+    /// opposed to leaving it at the default.
+    pub fn user_mask_feather_specified(&self) -> bool {
+        self.mask_flags().contains(LayerMaskFlag::UserMaskFeather)
+    }
+
+    /// Whether this record specified a vector mask density, as opposed to
+    /// leaving it at the default.
+    pub fn vector_mask_density_specified(&self) -> bool {
+        self.mask_flags().contains(LayerMaskFlag::VectorMaskDensity)
+    }
+
+    /// Whether this record specified a vector mask feather, as opposed to
+    /// leaving it at the default.
+    pub fn vector_mask_feather_specified(&self) -> bool {
+        self.mask_flags().contains(LayerMaskFlag::VectorMaskFeather)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(top: i32, left: i32, bottom: i32, right: i32, flags: u8, density: u8) -> LayerMaskDataInner {
+        LayerMaskDataInner {
+            top,
+            left,
+            bottom,
+            right,
+            default_color: 0,
+            flags,
+            parameter_flags: 0,
+            density,
+            feather: 0.0,
+        }
+    }
+
+    #[test]
+    fn rasterize_passes_through_an_unmodified_mask() {
+        let mask = mask(0, 0, 2, 2, 0, 255);
+        let channel = vec![10, 20, 30, 40];
+
+        assert_eq!(mask.rasterize(&channel), channel);
+    }
+
+    #[test]
+    fn rasterize_scales_by_density() {
+        let mask = mask(0, 0, 1, 2, 0, 128);
+        let channel = vec![255, 255];
+
+        let expected = (255.0_f32 * 128.0 / 255.0).round() as u8;
+        assert_eq!(mask.rasterize(&channel), vec![expected, expected]);
+    }
+
+    #[test]
+    fn rasterize_inverts_when_flag_set() {
+        let mask = mask(0, 0, 1, 4, 0b0000_0100, 255);
+        let channel = vec![0, 50, 200, 255];
+
+        assert_eq!(mask.rasterize(&channel), vec![255, 205, 55, 0]);
+    }
+
+    #[test]
+    fn rasterize_returns_full_opacity_when_disabled() {
+        let mask = mask(0, 0, 2, 2, 0b0000_0010, 255);
+        let channel = vec![0, 0, 0, 0];
+
+        assert_eq!(mask.rasterize(&channel), vec![255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn rasterize_refuses_to_allocate_for_an_absurd_rectangle() {
+        let mask = mask(0, 0, 100_000, 100_000, 0, 255);
+
+        assert_eq!(mask.rasterize(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn gaussian_kernel_radius_shrinks_for_a_large_pixel_count_even_with_a_huge_feather() {
+        // A mask near MAX_MASK_PIXELS paired with a feather that would
+        // otherwise hit MAX_GAUSSIAN_RADIUS on its own: gaussian_kernel must
+        // still be handed a much smaller radius, or the combined (pixel
+        // count * radius) work driving convolve_1d is unbounded.
+        let huge_sigma = 1_000.0;
+        let unclamped_kernel = gaussian_kernel(huge_sigma, MAX_GAUSSIAN_RADIUS);
+        assert_eq!(unclamped_kernel.len(), 2 * MAX_GAUSSIAN_RADIUS as usize + 1);
+
+        let width = 8_000;
+        let height = 8_000;
+        let max_radius =
+            (MAX_BLUR_WORK / (width * height)).clamp(1, MAX_GAUSSIAN_RADIUS as usize) as i32;
+        let bounded_kernel = gaussian_kernel(huge_sigma, max_radius);
+
+        assert!((bounded_kernel.len() as i32) < unclamped_kernel.len() as i32);
+        assert!((width * height) * max_radius as usize <= MAX_BLUR_WORK.max(width * height));
+    }
+
+    #[test]
+    fn masked_rgba_maps_canvas_absolute_mask_coordinates() {
+        let raster_mask = mask(10, 10, 12, 12, 0, 255);
+        let data = LayerMaskData {
+            vector_mask: None,
+            raster_mask: Some(raster_mask),
+        };
+        let channel = vec![255, 0, 255, 0];
+        let rgba = vec![255u8; 2 * 2 * 4];
+
+        let out = data.masked_rgba(10, 10, 12, 12, &rgba, None, Some(&channel));
+
+        assert_eq!(out[3], 255);
+        assert_eq!(out[7], 0);
+        assert_eq!(out[11], 255);
+        assert_eq!(out[15], 0);
+    }
+
+    #[test]
+    fn masked_rgba_maps_layer_relative_mask_coordinates() {
+        let raster_mask = mask(0, 0, 2, 2, 0b0000_0001, 255);
+        let data = LayerMaskData {
+            vector_mask: None,
+            raster_mask: Some(raster_mask),
+        };
+        let channel = vec![255, 0, 0, 255];
+        let rgba = vec![255u8; 2 * 2 * 4];
+
+        let out = data.masked_rgba(100, 100, 102, 102, &rgba, None, Some(&channel));
+
+        assert_eq!(out[3], 255);
+        assert_eq!(out[7], 0);
+        assert_eq!(out[11], 0);
+        assert_eq!(out[15], 255);
+    }
+
+    #[test]
+    fn masked_rgba_does_not_panic_when_rasterize_caps_an_absurd_mask() {
+        let raster_mask = mask(0, 0, 100_000, 100_000, 0b0000_0001, 255);
+        let data = LayerMaskData {
+            vector_mask: None,
+            raster_mask: Some(raster_mask),
+        };
+        let rgba = vec![255u8; 2 * 2 * 4];
+
+        let out = data.masked_rgba(0, 0, 2, 2, &rgba, None, Some(&[]));
+
+        assert_eq!(out.len(), rgba.len());
+    }
+
+    struct TestLayer {
+        bounds: (i32, i32, i32, i32),
+        rgba: Vec<u8>,
+        raster_mask_channel: Vec<u8>,
+    }
+
+    impl MaskChannelSource for TestLayer {
+        fn bounds(&self) -> (i32, i32, i32, i32) {
+            self.bounds
+        }
+
+        fn rgba(&self) -> &[u8] {
+            &self.rgba
+        }
+
+        fn vector_mask_channel(&self) -> Option<&[u8]> {
+            None
+        }
+
+        fn raster_mask_channel(&self) -> Option<&[u8]> {
+            Some(&self.raster_mask_channel)
+        }
+    }
+
+    #[test]
+    fn masked_rgba_for_fetches_bounds_and_channels_from_the_source() {
+        let data = LayerMaskData {
+            vector_mask: None,
+            raster_mask: Some(mask(0, 0, 1, 2, 0, 255)),
+        };
+        let layer = TestLayer {
+            bounds: (0, 0, 2, 1),
+            rgba: vec![255, 255, 255, 255, 255, 255, 255, 255],
+            raster_mask_channel: vec![255, 0],
+        };
+
+        let out = data.masked_rgba_for(&layer);
+
+        assert_eq!(out[3], 255);
+        assert_eq!(out[7], 0);
+    }
+
+    struct DecodeFailedRasterLayer {
+        vector_mask_channel: Vec<u8>,
+    }
+
+    impl MaskChannelSource for DecodeFailedRasterLayer {
+        fn bounds(&self) -> (i32, i32, i32, i32) {
+            (0, 0, 2, 1)
+        }
+
+        fn rgba(&self) -> &[u8] {
+            &[]
+        }
+
+        fn vector_mask_channel(&self) -> Option<&[u8]> {
+            Some(&self.vector_mask_channel)
+        }
+
+        fn raster_mask_channel(&self) -> Option<&[u8]> {
+            None
+        }
+    }
+
+    #[test]
+    fn rasterize_for_falls_back_to_the_vector_mask_when_the_raster_channel_failed_to_decode() {
+        let data = LayerMaskData {
+            vector_mask: Some(mask(0, 0, 1, 2, 0, 255)),
+            raster_mask: Some(mask(0, 0, 1, 2, 0, 255)),
+        };
+        let layer = DecodeFailedRasterLayer {
+            vector_mask_channel: vec![255, 0],
+        };
+
+        let out = data.rasterize_for(&layer).expect("vector mask channel is available");
+
+        assert_eq!(out, vec![255, 0]);
+    }
+
+    fn push_u32(buf: &mut Vec<u8>, value: u32) {
+        buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn push_i32(buf: &mut Vec<u8>, value: i32) {
+        buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    #[test]
+    fn ordinary_mask_without_parameters_defaults_density_to_opaque() {
+        let mut buf = Vec::new();
+        push_u32(&mut buf, 18); // layer_mask_data_len
+        push_i32(&mut buf, 0); // top
+        push_i32(&mut buf, 0); // left
+        push_i32(&mut buf, 10); // bottom
+        push_i32(&mut buf, 10); // right
+        buf.push(128); // default_color
+        buf.push(0); // flags: no parameters applied
+
+        let mut cursor = PsdCursor::new(&buf);
+        let result = read_layer_mask_data_with_mode(&mut cursor, MaskParseMode::Lenient).unwrap();
+
+        assert!(!result.diagnostics.truncated);
+        assert_eq!(result.data.raster_mask.unwrap().density, 255);
+    }
+
+    #[test]
+    fn lenient_mode_recovers_from_a_header_longer_than_its_declared_length() {
+        let mut buf = Vec::new();
+        push_u32(&mut buf, 16); // declares only 16 bytes, but the header below is 18
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        buf.push(0); // default_color
+        buf.push(0); // flags
+
+        let mut cursor = PsdCursor::new(&buf);
+        let result = read_layer_mask_data_with_mode(&mut cursor, MaskParseMode::Lenient).unwrap();
+
+        assert!(result.diagnostics.truncated);
+    }
+
+    #[test]
+    fn lenient_mode_realigns_the_cursor_to_the_declared_end_after_an_oversized_header() {
+        let mut buf = Vec::new();
+        push_u32(&mut buf, 16); // declares only 16 bytes, but the rect alone is 16, plus 2 more for color+flags
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        // These 2 bytes sit past the record's declared end (offset 4 + 16).
+        // The unconditional header read consumes them as default_color/flags,
+        // overshooting layer_mask_data_len by 2 - realigning must hand them
+        // back so the next field in the file reads them instead.
+        buf.push(0xAB);
+        buf.push(0xCD);
+
+        let mut cursor = PsdCursor::new(&buf);
+        read_layer_mask_data_with_mode(&mut cursor, MaskParseMode::Lenient).unwrap();
+
+        assert_eq!(cursor.position(), 20);
+        assert_eq!(cursor.read_u8(), 0xAB);
+        assert_eq!(cursor.read_u8(), 0xCD);
+    }
+
+    #[test]
+    fn strict_mode_rejects_a_header_longer_than_its_declared_length() {
+        let mut buf = Vec::new();
+        push_u32(&mut buf, 16);
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        push_i32(&mut buf, 0);
+        buf.push(0);
+        buf.push(0);
+
+        let mut cursor = PsdCursor::new(&buf);
+        let err = read_layer_mask_data_with_mode(&mut cursor, MaskParseMode::Strict).unwrap_err();
+
+        assert_eq!(err.layer_mask_data_len, 16);
+        assert_eq!(err.read_count, 18);
     }
 }